@@ -1,28 +1,327 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use std::net::SocketAddr;
 use tokio::time::sleep;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use reqwest::{Client, Proxy};
 use serde::{Deserialize, Serialize};
 use rand::seq::SliceRandom;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+/// Seconds since the Unix epoch, clamped to zero before 1970.
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Best-effort extraction of the target `(host, port)` from the start of an
+/// HTTP proxy request — either a `CONNECT host:port` line, an absolute request
+/// URI, or a `Host:` header. The port defaults to 443 for `CONNECT` and 80
+/// otherwise when the request omits it.
+fn parse_request_target(head: &[u8]) -> Option<(String, u16)> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.lines();
+
+    let request_line = lines.next()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("");
+    let is_connect = method.eq_ignore_ascii_case("CONNECT");
+
+    let host_port = if is_connect {
+        target.to_string()
+    } else if let Some(rest) = target.strip_prefix("http://").or_else(|| target.strip_prefix("https://")) {
+        rest.split('/').next().unwrap_or("").to_string()
+    } else {
+        lines
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.split_once(':').filter(|(k, _)| k.eq_ignore_ascii_case("host")))
+            .map(|(_, value)| value.trim().to_string())?
+    };
+
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()?),
+        None => (host_port.as_str(), if is_connect { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some((host.to_string(), port))
+    }
+}
+
+/// Encode a PROXY protocol header announcing the original `client` as the
+/// source and the request's real `destination` as the destination. v2 uses the
+/// `proxy-protocol` crate's binary encoding; v1 falls back to the text format
+/// for destinations that only speak it.
+fn encode_proxy_header(mode: ProxyProtocolMode, client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    // The PROXY protocol carries a single address family for both endpoints, so
+    // a mixed v4/v6 pair can't be represented. Surface it rather than silently
+    // emitting an address-less header that drops the client the feature exists
+    // to convey.
+    if client.is_ipv4() != destination.is_ipv4() {
+        eprintln!(
+            "PROXY header: client {} and destination {} use different address families; skipping header",
+            client, destination
+        );
+        return Vec::new();
+    }
+
+    match mode {
+        ProxyProtocolMode::V2 => {
+            use proxy_protocol::{version2, ProxyHeader};
+
+            let addresses = match (client, destination) {
+                (SocketAddr::V4(src), SocketAddr::V4(dst)) => version2::ProxyAddresses::Ipv4 {
+                    source: src,
+                    destination: dst,
+                },
+                (SocketAddr::V6(src), SocketAddr::V6(dst)) => version2::ProxyAddresses::Ipv6 {
+                    source: src,
+                    destination: dst,
+                },
+                _ => version2::ProxyAddresses::Unspecified,
+            };
+            let header = ProxyHeader::Version2 {
+                command: version2::ProxyCommand::Proxy,
+                transport_protocol: version2::ProxyTransportProtocol::Stream,
+                addresses,
+            };
+            proxy_protocol::encode(header)
+                .map(|bytes| bytes.to_vec())
+                .unwrap_or_default()
+        }
+        ProxyProtocolMode::V1 => {
+            let family = match (client, destination) {
+                (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+                (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+                _ => "UNKNOWN",
+            };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                client.ip(),
+                destination.ip(),
+                client.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolMode::Disabled => Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Http,
+    Https,
+    Socks4,
+    Socks5,
+}
+
+impl Protocol {
+    /// Infer the protocol a source advertises from its `type=` param or file
+    /// name (e.g. `type=socks5`, `socks5.txt`, `type=https`). The scheme is
+    /// ignored — every source is served over `https://` — so we match on the
+    /// path/query only. Defaults to HTTP.
+    fn from_source(url: &str) -> Protocol {
+        let url = url.to_lowercase();
+        // Drop the scheme so a `https://` prefix can't be mistaken for an
+        // HTTPS proxy type.
+        let marker = url.split_once("://").map(|(_, rest)| rest).unwrap_or(&url);
+        if marker.contains("socks5") {
+            Protocol::Socks5
+        } else if marker.contains("socks4") {
+            Protocol::Socks4
+        } else if marker.contains("https") {
+            Protocol::Https
+        } else {
+            Protocol::Http
+        }
+    }
+
+    /// The URL scheme reqwest expects for this protocol.
+    fn scheme(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+            Protocol::Socks4 => "socks4",
+            Protocol::Socks5 => "socks5",
+        }
+    }
+
+    /// Build a `reqwest::Proxy` for `ip:port`. HTTP and HTTPS entries only
+    /// intercept requests to `http://` and `https://` URLs respectively; SOCKS
+    /// entries use `Proxy::all` so reqwest's socks feature tunnels every scheme.
+    fn reqwest_proxy(&self, ip: &str, port: u16) -> reqwest::Result<Proxy> {
+        let url = format!("{}://{}:{}", self.scheme(), ip, port);
+        match self {
+            Protocol::Http => Proxy::http(&url),
+            Protocol::Https => Proxy::https(&url),
+            Protocol::Socks4 | Protocol::Socks5 => Proxy::all(&url),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyInfo {
     pub ip: String,
     pub port: u16,
     pub country: String,
-    pub protocol: String,
+    pub protocol: Protocol,
     pub speed: f64,
     pub success_rate: f64,
     #[serde(skip, default = "Instant::now")]
     pub last_tested: Instant,
+    /// Wall-clock timestamp of the last probe, persisted across restarts since
+    /// `last_tested` is `#[serde(skip)]`. Zero means never tested.
+    #[serde(default)]
+    pub last_tested_unix: u64,
     pub failures: u32,
 }
 
+/// Runtime configuration for a `ProxyManager`. Deserialized from YAML or JSON
+/// so operators can change sources, test endpoints and timing without a
+/// recompile.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub sources: Vec<String>,
+    pub country_sources: HashMap<String, Vec<String>>,
+    pub test_urls: Vec<String>,
+    pub test_timeout_secs: u64,
+    pub validate_timeout_secs: u64,
+    pub rotation_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut country_sources = HashMap::new();
+        for (country, url) in [
+            ("US", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_us.txt"),
+            ("UK", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_uk.txt"),
+            ("CA", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_ca.txt"),
+            ("DE", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_de.txt"),
+            ("FR", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_fr.txt"),
+        ] {
+            country_sources.insert(country.to_string(), vec![url.to_string()]);
+        }
+
+        Self {
+            sources: vec![
+                "https://www.proxy-list.download/api/v1/get?type=http".to_string(),
+                "https://raw.githubusercontent.com/TheSpeedX/PROXY-List/master/http.txt".to_string(),
+                "https://raw.githubusercontent.com/clarketm/proxy-list/master/proxy-list-raw.txt".to_string(),
+                "https://raw.githubusercontent.com/ShiftyTR/Proxy-List/master/http.txt".to_string(),
+                "https://raw.githubusercontent.com/monosans/proxy-list/main/proxies/http.txt".to_string(),
+                "https://www.proxy-list.download/api/v1/get?type=socks4".to_string(),
+                "https://www.proxy-list.download/api/v1/get?type=socks5".to_string(),
+                "https://raw.githubusercontent.com/TheSpeedX/PROXY-List/master/socks4.txt".to_string(),
+                "https://raw.githubusercontent.com/TheSpeedX/PROXY-List/master/socks5.txt".to_string(),
+            ],
+            country_sources,
+            test_urls: vec![
+                "http://httpbin.org/ip".to_string(),
+                "http://icanhazip.com".to_string(),
+                "http://ipinfo.io/ip".to_string(),
+            ],
+            test_timeout_secs: 10,
+            validate_timeout_secs: 5,
+            rotation_interval_secs: 3600,
+        }
+    }
+}
+
+impl Config {
+    /// Parse a config file, picking YAML or JSON by its extension.
+    fn from_file(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => serde_yaml::from_str(&text)?,
+        };
+        Ok(config)
+    }
+}
+
+/// Matches a request's target host, either exactly or against a compiled glob
+/// pattern such as `*.example.com`.
+#[derive(Debug, Clone)]
+pub enum HostMatcher {
+    Exact(String),
+    Glob(glob::Pattern),
+}
+
+impl HostMatcher {
+    /// Interpret `pattern` as a glob when it carries glob metacharacters,
+    /// otherwise as an exact hostname.
+    pub fn parse(pattern: &str) -> Result<HostMatcher, glob::PatternError> {
+        if pattern.contains(['*', '?', '[']) {
+            Ok(HostMatcher::Glob(glob::Pattern::new(pattern)?))
+        } else {
+            Ok(HostMatcher::Exact(pattern.to_string()))
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostMatcher::Exact(name) => name.eq_ignore_ascii_case(host),
+            HostMatcher::Glob(pattern) => pattern.matches(host),
+        }
+    }
+}
+
+/// How to pick a proxy once a routing rule matches.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionPolicy {
+    pub country: Option<String>,
+    pub protocol: Option<Protocol>,
+    pub min_success_rate: f64,
+}
+
+/// A host matcher bound to a selection policy, ordered by `priority`.
+#[derive(Debug, Clone)]
+pub struct RoutingRule {
+    pub matcher: HostMatcher,
+    pub priority: i32,
+    pub policy: SelectionPolicy,
+}
+
+impl RoutingRule {
+    pub fn new(pattern: &str, priority: i32, policy: SelectionPolicy) -> Result<RoutingRule, glob::PatternError> {
+        Ok(RoutingRule {
+            matcher: HostMatcher::parse(pattern)?,
+            priority,
+            policy,
+        })
+    }
+}
+
+/// Whether `serve` prepends a PROXY protocol header on the upstream connection
+/// so the destination can recover the original client's address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolMode {
+    /// Do not emit a header (for upstreams that don't understand it).
+    Disabled,
+    /// Binary PROXY protocol v2 header.
+    V2,
+    /// Human-readable PROXY protocol v1 text header.
+    V1,
+}
+
 #[derive(Debug, Clone)]
 pub struct ProxyManager {
     proxies: Arc<Mutex<Vec<ProxyInfo>>>,
     working_proxies: Arc<Mutex<HashMap<String, Vec<ProxyInfo>>>>,
+    routing_rules: Arc<Mutex<Vec<RoutingRule>>>,
+    config: Arc<Mutex<Config>>,
+    config_path: Option<PathBuf>,
     client: Client,
 }
 
@@ -31,25 +330,70 @@ impl ProxyManager {
         Self {
             proxies: Arc::new(Mutex::new(Vec::new())),
             working_proxies: Arc::new(Mutex::new(HashMap::new())),
+            routing_rules: Arc::new(Mutex::new(Vec::new())),
+            config: Arc::new(Mutex::new(Config::default())),
+            config_path: None,
             client: Client::new(),
         }
     }
 
+    /// Build a manager whose configuration is loaded from `path` and can be
+    /// hot-reloaded later via `reload` or a SIGHUP.
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let config = Config::from_file(&path)?;
+        Ok(Self {
+            proxies: Arc::new(Mutex::new(Vec::new())),
+            working_proxies: Arc::new(Mutex::new(HashMap::new())),
+            routing_rules: Arc::new(Mutex::new(Vec::new())),
+            config: Arc::new(Mutex::new(config)),
+            config_path: Some(path),
+            client: Client::new(),
+        })
+    }
+
+    /// Re-read the backing config file and swap the new values in place. A no-op
+    /// when the manager was not created from a file.
+    pub fn reload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let new_config = Config::from_file(path)?;
+        *self.config.lock().unwrap() = new_config;
+        Ok(())
+    }
+
+    /// Listen for SIGHUP on a background thread and `reload` the config on each
+    /// one, so a long-running `rotate_proxies` loop picks up edited sources
+    /// without a restart.
+    pub fn install_sighup_reload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        use signal_hook::consts::SIGHUP;
+        use signal_hook::iterator::Signals;
+
+        let manager = self.clone();
+        let mut signals = Signals::new([SIGHUP])?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                match manager.reload() {
+                    Ok(()) => println!("Config reloaded on SIGHUP"),
+                    Err(e) => eprintln!("Error reloading config: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
     pub async fn fetch_free_proxies(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let sources = vec![
-            "https://www.proxy-list.download/api/v1/get?type=http",
-            "https://raw.githubusercontent.com/TheSpeedX/PROXY-List/master/http.txt",
-            "https://raw.githubusercontent.com/clarketm/proxy-list/master/proxy-list-raw.txt",
-            "https://raw.githubusercontent.com/ShiftyTR/Proxy-List/master/http.txt",
-            "https://raw.githubusercontent.com/monosans/proxy-list/main/proxies/http.txt",
-        ];
+        let sources = { self.config.lock().unwrap().sources.clone() };
 
         let mut all_proxies = Vec::new();
 
         for source in sources {
-            if let Ok(response) = self.client.get(source).send().await {
+            let protocol = Protocol::from_source(&source);
+            if let Ok(response) = self.client.get(&source).send().await {
                 if let Ok(text) = response.text().await {
-                    let proxies = self.parse_proxy_list(&text);
+                    let proxies = self.parse_proxy_list(&text, protocol);
                     all_proxies.extend(proxies);
                 }
             }
@@ -58,35 +402,38 @@ impl ProxyManager {
         // Add country-specific proxy sources
         self.fetch_country_proxies(&mut all_proxies).await;
 
+        // Replace (not append) the fetched set each round, deduped by endpoint,
+        // so a long-running rotation doesn't re-probe an ever-growing list of
+        // stale duplicates. Validation history still survives via the working
+        // set, which seeds each proxy's stats before re-testing.
+        let mut seen = std::collections::HashSet::new();
+        all_proxies.retain(|proxy| seen.insert((proxy.ip.clone(), proxy.port)));
+
         let mut proxy_list = self.proxies.lock().unwrap();
-        proxy_list.extend(all_proxies);
-        
+        *proxy_list = all_proxies;
+
         Ok(())
     }
 
     async fn fetch_country_proxies(&self, proxies: &mut Vec<ProxyInfo>) {
-        let country_sources = vec![
-            ("US", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_us.txt"),
-            ("UK", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_uk.txt"),
-            ("CA", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_ca.txt"),
-            ("DE", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_de.txt"),
-            ("FR", "https://raw.githubusercontent.com/proxy4parsing/proxy-list/main/http_fr.txt"),
-        ];
+        let country_sources = { self.config.lock().unwrap().country_sources.clone() };
 
-        for (country, url) in country_sources {
-            if let Ok(response) = self.client.get(url).send().await {
-                if let Ok(text) = response.text().await {
-                    let mut country_proxies = self.parse_proxy_list(&text);
-                    for proxy in &mut country_proxies {
-                        proxy.country = country.to_string();
+        for (country, urls) in country_sources {
+            for url in urls {
+                if let Ok(response) = self.client.get(&url).send().await {
+                    if let Ok(text) = response.text().await {
+                        let mut country_proxies = self.parse_proxy_list(&text, Protocol::from_source(&url));
+                        for proxy in &mut country_proxies {
+                            proxy.country = country.clone();
+                        }
+                        proxies.extend(country_proxies);
                     }
-                    proxies.extend(country_proxies);
                 }
             }
         }
     }
 
-    fn parse_proxy_list(&self, text: &str) -> Vec<ProxyInfo> {
+    fn parse_proxy_list(&self, text: &str, protocol: Protocol) -> Vec<ProxyInfo> {
         let mut proxies = Vec::new();
         
         for line in text.lines() {
@@ -101,10 +448,11 @@ impl ProxyManager {
                         ip: ip.to_string(),
                         port,
                         country: "Unknown".to_string(),
-                        protocol: "http".to_string(),
+                        protocol,
                         speed: 0.0,
                         success_rate: 0.0,
                         last_tested: Instant::now(),
+                        last_tested_unix: 0,
                         failures: 0,
                     });
                 }
@@ -114,84 +462,206 @@ impl ProxyManager {
         proxies
     }
 
+    /// Default concurrency for `test_proxies`.
+    const DEFAULT_TEST_CONCURRENCY: usize = 100;
+    /// Smoothing factor for the exponential moving average of proxy stats.
+    const EWMA_ALPHA: f64 = 0.3;
+    /// Consecutive missed rounds after which a proxy is retired from the set.
+    const FAILURE_THRESHOLD: u32 = 5;
+
     pub async fn test_proxies(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let proxies = {
+        self.test_proxies_with_concurrency(Self::DEFAULT_TEST_CONCURRENCY).await
+    }
+
+    /// Validate every fetched proxy with at most `limit` probes in flight at
+    /// once. Each proxy gets its own validation future that acquires a
+    /// semaphore permit before running its timeout-bounded probes and pushes
+    /// the result down a channel the collector drains into `working_proxies`.
+    pub async fn test_proxies_with_concurrency(&self, limit: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let mut proxies = {
             let proxy_list = self.proxies.lock().unwrap();
             proxy_list.clone()
         };
 
-        let mut working = HashMap::new();
-        let test_urls = vec![
-            "http://httpbin.org/ip",
-            "http://icanhazip.com",
-            "http://ipinfo.io/ip",
-        ];
+        // Seed freshly-fetched proxies with any history we already hold so the
+        // EWMA update below continues from past behavior rather than cold.
+        {
+            let working_proxies = self.working_proxies.lock().unwrap();
+            let mut history: HashMap<String, &ProxyInfo> = HashMap::new();
+            for bucket in working_proxies.values() {
+                for proxy in bucket {
+                    history.insert(format!("{}:{}", proxy.ip, proxy.port), proxy);
+                }
+            }
+            for proxy in &mut proxies {
+                if let Some(prev) = history.get(&format!("{}:{}", proxy.ip, proxy.port)) {
+                    proxy.speed = prev.speed;
+                    proxy.success_rate = prev.success_rate;
+                    proxy.failures = prev.failures;
+                    proxy.last_tested_unix = prev.last_tested_unix;
+                }
+            }
+        }
+
+        let (test_urls, timeout) = {
+            let config = self.config.lock().unwrap();
+            (config.test_urls.clone(), Duration::from_secs(config.test_timeout_secs))
+        };
+        let test_urls = Arc::new(test_urls);
+
+        let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+        let (tx, mut rx) = mpsc::channel::<ProxyInfo>(limit.max(1));
 
+        let mut tasks = FuturesUnordered::new();
         for proxy in proxies {
-            let proxy_url = format!("http://{}:{}", proxy.ip, proxy.port);
-            
-            if let Ok(proxy_client) = reqwest::Client::builder()
-                .proxy(Proxy::http(&proxy_url)?)
-                .timeout(Duration::from_secs(10))
-                .build()
-            {
-                let start = Instant::now();
-                let mut success_count = 0;
-
-                for test_url in &test_urls {
-                    if let Ok(response) = proxy_client.get(*test_url).send().await {
-                        if response.status().is_success() {
-                            success_count += 1;
-                        }
-                    }
+            let permit_source = Arc::clone(&semaphore);
+            let test_urls = Arc::clone(&test_urls);
+            let tx = tx.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = match permit_source.acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                let (sample_rate, sample_speed) = Self::probe_proxy(&proxy, &test_urls, timeout).await;
+                if let Some(tested) = Self::apply_sample(proxy, sample_rate, sample_speed) {
+                    let _ = tx.send(tested).await;
                 }
+            }));
+        }
+        drop(tx);
 
-                let speed = start.elapsed().as_millis() as f64;
-                let success_rate = success_count as f64 / test_urls.len() as f64;
+        let mut working: HashMap<String, Vec<ProxyInfo>> = HashMap::new();
+        let collector = async {
+            while let Some(proxy) = rx.recv().await {
+                working.entry(proxy.country.clone())
+                    .or_insert_with(Vec::new)
+                    .push(proxy);
+            }
+        };
+        let drainer = async { while tasks.next().await.is_some() {} };
+        tokio::join!(collector, drainer);
 
-                if success_rate > 0.5 {
-                    let mut updated_proxy = proxy.clone();
-                    updated_proxy.speed = speed;
-                    updated_proxy.success_rate = success_rate;
-                    updated_proxy.last_tested = Instant::now();
+        let mut working_proxies = self.working_proxies.lock().unwrap();
+        *working_proxies = working;
 
-                    working.entry(updated_proxy.country.clone())
-                        .or_insert_with(Vec::new)
-                        .push(updated_proxy);
+        Ok(())
+    }
+
+    /// Run the timeout-bounded probes against a single proxy, returning the
+    /// sampled `(success_rate, speed_ms)` for this round. A client that cannot
+    /// even be built counts as a fully failed sample.
+    async fn probe_proxy(proxy: &ProxyInfo, test_urls: &[String], timeout: Duration) -> (f64, f64) {
+        let reqwest_proxy = match proxy.protocol.reqwest_proxy(&proxy.ip, proxy.port) {
+            Ok(proxy) => proxy,
+            Err(_) => return (0.0, timeout.as_millis() as f64),
+        };
+        let proxy_client = match reqwest::Client::builder()
+            .proxy(reqwest_proxy)
+            .timeout(timeout)
+            .build()
+        {
+            Ok(client) => client,
+            Err(_) => return (0.0, timeout.as_millis() as f64),
+        };
+
+        let start = Instant::now();
+        let mut success_count = 0;
+        for test_url in test_urls {
+            if let Ok(response) = proxy_client.get(test_url).send().await {
+                if response.status().is_success() {
+                    success_count += 1;
                 }
             }
         }
 
-        let mut working_proxies = self.working_proxies.lock().unwrap();
-        *working_proxies = working;
+        let speed = start.elapsed().as_millis() as f64;
+        let success_rate = success_count as f64 / test_urls.len().max(1) as f64;
+        (success_rate, speed)
+    }
+
+    /// Fold a fresh sample into a proxy's exponential moving average and track
+    /// consecutive misses. A proxy is only dropped once it has been retired
+    /// (`failures >= FAILURE_THRESHOLD`); entries with history are otherwise
+    /// kept and penalized round-over-round so their decaying score and failure
+    /// count persist across restarts. A brand-new proxy that fails its very
+    /// first probe is never admitted in the first place.
+    fn apply_sample(mut proxy: ProxyInfo, sample_rate: f64, sample_speed: f64) -> Option<ProxyInfo> {
+        let known = proxy.last_tested_unix != 0;
+        let alpha = Self::EWMA_ALPHA;
+        if !known {
+            // First observation: seed the average directly instead of decaying
+            // from a cold zero.
+            proxy.success_rate = sample_rate;
+            proxy.speed = sample_speed;
+        } else {
+            proxy.success_rate = alpha * sample_rate + (1.0 - alpha) * proxy.success_rate;
+            proxy.speed = alpha * sample_speed + (1.0 - alpha) * proxy.speed;
+        }
+
+        if sample_rate > 0.5 {
+            proxy.failures = 0;
+        } else {
+            proxy.failures += 1;
+        }
+
+        proxy.last_tested = Instant::now();
+        proxy.last_tested_unix = now_unix();
+
+        // Retire a proxy once it has missed too many rounds.
+        if proxy.failures >= Self::FAILURE_THRESHOLD {
+            return None;
+        }
+        // Never admit a proxy that has no history and failed its first probe;
+        // known proxies keep decaying until they retire.
+        if !known && sample_rate <= 0.5 {
+            return None;
+        }
+        Some(proxy)
+    }
+
+    /// Persist the current working set to `path` as JSON so validation history
+    /// survives a restart.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let working_proxies = self.working_proxies.lock().unwrap();
+        let json = serde_json::to_string_pretty(&*working_proxies)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
 
+    /// Load a previously-saved working set from `path`, replacing the current
+    /// one. Selection then starts with history instead of from scratch.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let loaded: HashMap<String, Vec<ProxyInfo>> = serde_json::from_str(&text)?;
+        *self.working_proxies.lock().unwrap() = loaded;
         Ok(())
     }
 
-    pub fn get_proxy_by_country(&self, country: &str) -> Option<ProxyInfo> {
+    pub fn get_proxy_by_country(&self, country: &str, protocol: Option<Protocol>) -> Option<ProxyInfo> {
         let working_proxies = self.working_proxies.lock().unwrap();
-        
+        let matches = |p: &ProxyInfo| protocol.map_or(true, |want| p.protocol == want);
+
         if let Some(country_proxies) = working_proxies.get(country) {
-            if !country_proxies.is_empty() {
-                let mut rng = rand::thread_rng();
-                return country_proxies.choose(&mut rng).cloned();
+            let mut rng = rand::thread_rng();
+            if let Some(proxy) = country_proxies.iter().filter(|p| matches(p)).collect::<Vec<_>>().choose(&mut rng) {
+                return Some((*proxy).clone());
             }
         }
 
-        // Fallback to any working proxy
+        // Fallback to any working proxy matching the required protocol
         for proxies in working_proxies.values() {
-            if !proxies.is_empty() {
-                let mut rng = rand::thread_rng();
-                return proxies.choose(&mut rng).cloned();
+            let mut rng = rand::thread_rng();
+            if let Some(proxy) = proxies.iter().filter(|p| matches(p)).collect::<Vec<_>>().choose(&mut rng) {
+                return Some((*proxy).clone());
             }
         }
 
         None
     }
 
-    pub fn get_best_proxy(&self, country: Option<&str>) -> Option<ProxyInfo> {
+    pub fn get_best_proxy(&self, country: Option<&str>, protocol: Option<Protocol>) -> Option<ProxyInfo> {
         let working_proxies = self.working_proxies.lock().unwrap();
-        
+
         let mut best_proxy: Option<ProxyInfo> = None;
         let mut best_score = 0.0;
 
@@ -207,6 +677,69 @@ impl ProxyManager {
 
         for proxies in proxies_to_check {
             for proxy in proxies {
+                if protocol.map_or(false, |want| proxy.protocol != want) {
+                    continue;
+                }
+                let score = proxy.success_rate * 100.0 - proxy.speed / 10.0;
+                if score > best_score {
+                    best_score = score;
+                    best_proxy = Some(proxy.clone());
+                }
+            }
+        }
+
+        best_proxy
+    }
+
+    /// Append a host routing rule. Rules are consulted highest-priority-first
+    /// when resolving `get_proxy_for_host`.
+    pub fn add_routing_rule(&self, rule: RoutingRule) {
+        self.routing_rules.lock().unwrap().push(rule);
+    }
+
+    /// Resolve the highest-priority rule matching `host` into a concrete proxy
+    /// from the working set. Falls back to the best proxy overall when no rule
+    /// matches.
+    pub fn get_proxy_for_host(&self, host: &str) -> Option<ProxyInfo> {
+        let policy = {
+            let rules = self.routing_rules.lock().unwrap();
+            rules
+                .iter()
+                .filter(|rule| rule.matcher.matches(host))
+                .max_by_key(|rule| rule.priority)
+                .map(|rule| rule.policy.clone())
+        };
+
+        match policy {
+            Some(policy) => self.select_by_policy(&policy),
+            None => self.get_best_proxy(None, None),
+        }
+    }
+
+    /// Pick the highest-scoring working proxy satisfying a selection policy.
+    fn select_by_policy(&self, policy: &SelectionPolicy) -> Option<ProxyInfo> {
+        let working_proxies = self.working_proxies.lock().unwrap();
+
+        let buckets: Vec<&Vec<ProxyInfo>> = match &policy.country {
+            Some(country) => match working_proxies.get(country) {
+                Some(bucket) => vec![bucket],
+                None => working_proxies.values().collect(),
+            },
+            None => working_proxies.values().collect(),
+        };
+
+        let mut best_proxy: Option<ProxyInfo> = None;
+        // Seed below any real score so even a slow-but-working proxy (negative
+        // score) is selectable once its rule has matched.
+        let mut best_score = f64::NEG_INFINITY;
+        for bucket in buckets {
+            for proxy in bucket {
+                if policy.protocol.map_or(false, |want| proxy.protocol != want) {
+                    continue;
+                }
+                if proxy.success_rate < policy.min_success_rate {
+                    continue;
+                }
                 let score = proxy.success_rate * 100.0 - proxy.speed / 10.0;
                 if score > best_score {
                     best_score = score;
@@ -218,10 +751,142 @@ impl ProxyManager {
         best_proxy
     }
 
-    pub async fn rotate_proxies(&self, interval_seconds: u64) {
+    /// Rank the working HTTP(S) proxies usable as serve-mode upstreams, best
+    /// first. SOCKS entries are excluded because serve mode replays raw HTTP
+    /// bytes without a SOCKS handshake. When a routing rule matches `host`, its
+    /// country/`min_success_rate` policy is applied (the required protocol is
+    /// not, since serve mode always needs HTTP(S)).
+    fn serve_upstreams(&self, host: Option<&str>) -> Vec<ProxyInfo> {
+        let policy = host.and_then(|host| {
+            let rules = self.routing_rules.lock().unwrap();
+            rules
+                .iter()
+                .filter(|rule| rule.matcher.matches(host))
+                .max_by_key(|rule| rule.priority)
+                .map(|rule| rule.policy.clone())
+        });
+
+        let working_proxies = self.working_proxies.lock().unwrap();
+        let mut candidates: Vec<ProxyInfo> = working_proxies
+            .values()
+            .flatten()
+            .filter(|proxy| matches!(proxy.protocol, Protocol::Http | Protocol::Https))
+            .filter(|proxy| {
+                policy.as_ref().map_or(true, |policy| {
+                    policy.country.as_deref().map_or(true, |c| proxy.country == c)
+                        && proxy.success_rate >= policy.min_success_rate
+                })
+            })
+            .cloned()
+            .collect();
+
+        candidates.sort_by(|a, b| {
+            let score = |p: &ProxyInfo| p.success_rate * 100.0 - p.speed / 10.0;
+            score(b).partial_cmp(&score(a)).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        candidates
+    }
+
+    /// Listen on `listen_addr` and forward every inbound connection through an
+    /// HTTP(S) upstream proxy chosen by the routing rules (falling back to the
+    /// best proxy overall), trying the next-best upstream on connect failure.
+    ///
+    /// When `header_mode` is not `Disabled` the connection is instead tunnelled
+    /// *directly* to the resolved destination with a PROXY protocol header
+    /// prepended, so a PROXY-aware destination sees the original client. This
+    /// bypasses upstream rotation on purpose: an HTTP forward proxy would read
+    /// the header as the request line and reject it, so the header can only
+    /// reach a destination we connect to ourselves.
+    pub async fn serve<A: tokio::net::ToSocketAddrs>(
+        &self,
+        listen_addr: A,
+        header_mode: ProxyProtocolMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(listen_addr).await?;
+
         loop {
+            let (inbound, client_addr) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+
+            let manager = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = manager.forward_connection(inbound, client_addr, header_mode).await {
+                    eprintln!("Error forwarding connection from {}: {}", client_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn forward_connection(
+        &self,
+        mut inbound: TcpStream,
+        client_addr: SocketAddr,
+        header_mode: ProxyProtocolMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Read the first chunk so we can route on the requested host; the bytes
+        // are then replayed to the upstream verbatim.
+        let mut head = vec![0u8; 8192];
+        let n = inbound.read(&mut head).await?;
+        head.truncate(n);
+        let target = parse_request_target(&head);
+        let host = target.as_ref().map(|(host, _)| host.clone());
+
+        // Resolve the real destination so a PROXY header (if enabled) names the
+        // client's actual target rather than our local upstream socket.
+        let dest_addr = match &target {
+            Some((host, port)) => tokio::net::lookup_host((host.as_str(), *port))
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.next()),
+            None => None,
+        };
+
+        // With headers enabled, tunnel straight to the destination: an HTTP
+        // forward proxy would parse the PROXY header as the request line, so
+        // the header only reaches the destination when we connect to it.
+        if header_mode != ProxyProtocolMode::Disabled {
+            let dest = dest_addr.ok_or("cannot resolve destination for PROXY-protocol passthrough")?;
+            let mut outbound = TcpStream::connect(dest).await?;
+            let header = encode_proxy_header(header_mode, client_addr, dest);
+            outbound.write_all(&header).await?;
+            outbound.write_all(&head).await?;
+            tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+            return Ok(());
+        }
+
+        let upstreams = self.serve_upstreams(host.as_deref());
+        if upstreams.is_empty() {
+            return Err("no working HTTP upstream proxy available".into());
+        }
+
+        // Try the three best-ranked upstreams in turn, failing over on connect
+        // error.
+        for upstream in upstreams.into_iter().take(3) {
+            let addr = format!("{}:{}", upstream.ip, upstream.port);
+            let mut outbound = match TcpStream::connect(&addr).await {
+                Ok(stream) => stream,
+                Err(_) => continue, // reselect
+            };
+
+            outbound.write_all(&head).await?;
+
+            tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+            return Ok(());
+        }
+
+        Err("exhausted upstream proxies".into())
+    }
+
+    pub async fn rotate_proxies(&self) {
+        loop {
+            let interval_seconds = { self.config.lock().unwrap().rotation_interval_secs };
             sleep(Duration::from_secs(interval_seconds)).await;
-            
+
             if let Err(e) = self.fetch_free_proxies().await {
                 eprintln!("Error fetching proxies: {}", e);
             }
@@ -246,18 +911,28 @@ impl ProxyManager {
     }
 
     pub async fn validate_proxy(&self, proxy: &ProxyInfo) -> bool {
-        let proxy_url = format!("http://{}:{}", proxy.ip, proxy.port);
-        
+        let (test_url, timeout) = {
+            let config = self.config.lock().unwrap();
+            let url = config.test_urls.first().cloned()
+                .unwrap_or_else(|| "http://httpbin.org/ip".to_string());
+            (url, Duration::from_secs(config.validate_timeout_secs))
+        };
+
+        let reqwest_proxy = match proxy.protocol.reqwest_proxy(&proxy.ip, proxy.port) {
+            Ok(proxy) => proxy,
+            Err(_) => return false,
+        };
+
         if let Ok(proxy_client) = reqwest::Client::builder()
-            .proxy(Proxy::http(&proxy_url).unwrap())
-            .timeout(Duration::from_secs(5))
+            .proxy(reqwest_proxy)
+            .timeout(timeout)
             .build()
         {
-            if let Ok(response) = proxy_client.get("http://httpbin.org/ip").send().await {
+            if let Ok(response) = proxy_client.get(&test_url).send().await {
                 return response.status().is_success();
             }
         }
-        
+
         false
     }
 }
@@ -265,22 +940,130 @@ impl ProxyManager {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let proxy_manager = ProxyManager::new();
-    
+
+    // Reuse validation history from a previous run if we have it.
+    let cache_path = "proxy_cache.json";
+    let _ = proxy_manager.load(cache_path);
+
     println!("Fetching free proxies...");
     proxy_manager.fetch_free_proxies().await?;
-    
+
     println!("Testing proxies...");
     proxy_manager.test_proxies().await?;
-    
+
+    if let Err(e) = proxy_manager.save(cache_path) {
+        eprintln!("Error saving proxy cache: {}", e);
+    }
+
     let stats = proxy_manager.get_proxy_stats();
     println!("Working proxies by country: {:?}", stats);
     
-    if let Some(us_proxy) = proxy_manager.get_proxy_by_country("US") {
+    if let Some(us_proxy) = proxy_manager.get_proxy_by_country("US", None) {
         println!("US Proxy: {}:{}", us_proxy.ip, us_proxy.port);
     }
     
-    // Start continuous rotation
-    proxy_manager.rotate_proxies(3600).await; // Rotate every hour
-    
+    // Install the SIGHUP handler and start continuous rotation. This manager
+    // was built with `new()`, not from a config file, so a SIGHUP reload here
+    // leaves the built-in sources unchanged.
+    proxy_manager.install_sighup_reload()?;
+    proxy_manager.rotate_proxies().await;
+
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(ip: &str, country: &str, protocol: Protocol, success_rate: f64) -> ProxyInfo {
+        ProxyInfo {
+            ip: ip.to_string(),
+            port: 8080,
+            country: country.to_string(),
+            protocol,
+            speed: 100.0,
+            success_rate,
+            last_tested: Instant::now(),
+            last_tested_unix: 0,
+            failures: 0,
+        }
+    }
+
+    #[test]
+    fn from_source_infers_type_not_scheme() {
+        // Every source is served over https://, so the scheme must not decide.
+        assert_eq!(Protocol::from_source("https://www.proxy-list.download/api/v1/get?type=http"), Protocol::Http);
+        assert_eq!(Protocol::from_source("https://example.com/proxies/http.txt"), Protocol::Http);
+        assert_eq!(Protocol::from_source("https://example.com/api?type=https"), Protocol::Https);
+        assert_eq!(Protocol::from_source("https://example.com/socks4.txt"), Protocol::Socks4);
+        assert_eq!(Protocol::from_source("https://www.proxy-list.download/api/v1/get?type=socks5"), Protocol::Socks5);
+    }
+
+    #[test]
+    fn host_matcher_exact_and_glob() {
+        let exact = HostMatcher::parse("example.com").unwrap();
+        assert!(matches!(exact, HostMatcher::Exact(_)));
+        assert!(exact.matches("EXAMPLE.COM"));
+        assert!(!exact.matches("api.example.com"));
+
+        let glob = HostMatcher::parse("*.example.com").unwrap();
+        assert!(matches!(glob, HostMatcher::Glob(_)));
+        assert!(glob.matches("api.example.com"));
+        assert!(!glob.matches("example.com"));
+    }
+
+    #[test]
+    fn routing_picks_highest_priority_rule() {
+        let manager = ProxyManager::new();
+        {
+            let mut working = manager.working_proxies.lock().unwrap();
+            working.insert("US".to_string(), vec![proxy("1.1.1.1", "US", Protocol::Http, 0.9)]);
+            working.insert("DE".to_string(), vec![proxy("2.2.2.2", "DE", Protocol::Http, 0.9)]);
+        }
+        manager.add_routing_rule(
+            RoutingRule::new("*.example.com", 1, SelectionPolicy { country: Some("DE".to_string()), ..Default::default() }).unwrap(),
+        );
+        manager.add_routing_rule(
+            RoutingRule::new("*.example.com", 10, SelectionPolicy { country: Some("US".to_string()), ..Default::default() }).unwrap(),
+        );
+
+        let chosen = manager.get_proxy_for_host("api.example.com").unwrap();
+        assert_eq!(chosen.ip, "1.1.1.1");
+    }
+
+    #[test]
+    fn apply_sample_seeds_then_decays() {
+        // A fresh proxy seeds its average from the first good sample.
+        let fresh = proxy("1.1.1.1", "US", Protocol::Http, 0.0);
+        let seeded = ProxyManager::apply_sample(fresh, 1.0, 50.0).unwrap();
+        assert!((seeded.success_rate - 1.0).abs() < 1e-9);
+
+        // A known proxy blends new samples via the EWMA.
+        let mut known = proxy("1.1.1.1", "US", Protocol::Http, 0.9);
+        known.last_tested_unix = 1;
+        let blended = ProxyManager::apply_sample(known, 0.0, 500.0).unwrap();
+        // 0.3 * 0.0 + 0.7 * 0.9 = 0.63, still retained despite a miss.
+        assert!((blended.success_rate - 0.63).abs() < 1e-9);
+        assert_eq!(blended.failures, 1);
+    }
+
+    #[test]
+    fn apply_sample_admission_and_retirement() {
+        // A brand-new proxy that fails its first probe is never admitted.
+        let fresh = proxy("1.1.1.1", "US", Protocol::Http, 0.0);
+        assert!(ProxyManager::apply_sample(fresh, 0.0, 500.0).is_none());
+
+        // A known proxy below 0.5 is kept (and penalized) rather than evicted.
+        let mut decayed = proxy("1.1.1.1", "US", Protocol::Http, 0.6);
+        decayed.last_tested_unix = 1;
+        let kept = ProxyManager::apply_sample(decayed, 0.0, 500.0).unwrap();
+        assert!(kept.success_rate < 0.5);
+        assert_eq!(kept.failures, 1);
+
+        // It is only retired once failures reach the threshold.
+        let mut almost = proxy("1.1.1.1", "US", Protocol::Http, 0.9);
+        almost.last_tested_unix = 1;
+        almost.failures = ProxyManager::FAILURE_THRESHOLD - 1;
+        assert!(ProxyManager::apply_sample(almost, 0.0, 500.0).is_none());
+    }
 }
\ No newline at end of file